@@ -14,11 +14,17 @@ extern crate serde_json;
 extern crate mktemp;
 #[macro_use]
 extern crate quick_error;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate prometheus;
+extern crate ws;
 
 use std::any::Any;
 use std::convert::{TryFrom, TryInto};
 use std::env;
 use std::path::PathBuf;
+use std::thread;
 
 use iron::prelude::*;
 use iron::status;
@@ -30,8 +36,30 @@ use sandbox::Sandbox;
 
 const DEFAULT_ADDRESS: &'static str = "127.0.0.1";
 const DEFAULT_PORT: u16 = 5000;
+const DEFAULT_STREAM_PORT: u16 = 5001;
 
 mod sandbox;
+mod stream;
+
+lazy_static! {
+    static ref REQUEST_COUNTER: prometheus::CounterVec = register_counter_vec!(
+        "playground_requests_total",
+        "Total number of requests handled, by route and outcome",
+        &["route", "outcome"]
+    ).unwrap();
+
+    static ref REQUEST_DURATION: prometheus::HistogramVec = register_histogram_vec!(
+        "playground_request_duration_seconds",
+        "Wall-clock duration of each sandbox invocation, by route",
+        &["route"]
+    ).unwrap();
+
+    static ref REQUEST_ERRORS: prometheus::CounterVec = register_counter_vec!(
+        "playground_request_errors_total",
+        "Total number of failed requests, by route and error variant",
+        &["route", "variant"]
+    ).unwrap();
+}
 
 fn main() {
     env_logger::init().expect("Unable to initialize logger");
@@ -39,19 +67,28 @@ fn main() {
     let root: PathBuf = env::var_os("PLAYGROUND_UI_ROOT").expect("Must specify PLAYGROUND_UI_ROOT").into();
     let address = env::var("PLAYGROUND_UI_ADDRESS").unwrap_or(DEFAULT_ADDRESS.to_string());
     let port = env::var("PLAYGROUND_UI_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(DEFAULT_PORT);
+    let stream_port = env::var("PLAYGROUND_UI_STREAM_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(DEFAULT_STREAM_PORT);
+
+    {
+        let address = address.clone();
+        thread::spawn(move || stream::listen(&address, stream_port));
+    }
 
     let mut mount = Mount::new();
     mount.mount("/", Static::new(&root));
     mount.mount("/compile", compile);
     mount.mount("/execute", execute);
     mount.mount("/format", format);
+    mount.mount("/metrics", metrics);
+    mount.mount("/rpc", rpc);
+    mount.mount("/clippy", clippy);
 
     info!("Starting the server on {}:{}", address, port);
     Iron::new(mount).http((&*address, port)).expect("Unable to start server");
 }
 
 fn compile(req: &mut Request) -> IronResult<Response> {
-    with_sandbox(req, |sandbox, req: CompileRequest| {
+    with_sandbox("compile", req, |sandbox, req: CompileRequest| {
         let req = try!(req.try_into());
         sandbox
             .compile(&req)
@@ -61,7 +98,7 @@ fn compile(req: &mut Request) -> IronResult<Response> {
 }
 
 fn execute(req: &mut Request) -> IronResult<Response> {
-    with_sandbox(req, |sandbox, req: ExecuteRequest| {
+    with_sandbox("execute", req, |sandbox, req: ExecuteRequest| {
         let req = try!(req.try_into());
         sandbox
             .execute(&req)
@@ -71,7 +108,7 @@ fn execute(req: &mut Request) -> IronResult<Response> {
 }
 
 fn format(req: &mut Request) -> IronResult<Response> {
-    with_sandbox(req, |sandbox, req: FormatRequest| {
+    with_sandbox("format", req, |sandbox, req: FormatRequest| {
         sandbox
             .format(&req.into())
             .map(FormatResponse::from)
@@ -79,11 +116,181 @@ fn format(req: &mut Request) -> IronResult<Response> {
     })
 }
 
-fn with_sandbox<Req, Resp, F>(req: &mut Request, f: F) -> IronResult<Response>
+fn clippy(req: &mut Request) -> IronResult<Response> {
+    with_sandbox("clippy", req, |sandbox, req: CompileRequest| {
+        let req = try!(req.try_into());
+        sandbox
+            .clippy(&req)
+            .map(LintResponse::from)
+            .map_err(Error::Sandbox)
+    })
+}
+
+fn rpc(req: &mut Request) -> IronResult<Response> {
+    // A `/rpc` body is either a single call object or a batch array of them;
+    // the old `serde_macros` plugin this crate is pinned to has no untagged
+    // enum support, so the two shapes are told apart by hand on the raw
+    // `Value` instead of via derive.
+    let payload = req.get::<bodyparser::Struct<serde_json::Value>>()
+        .map_err(Error::Deserialization)
+        .and_then(|r| r.ok_or(Error::RequestMissing));
+
+    let body = match payload {
+        Ok(serde_json::Value::Array(values)) => {
+            let responses: Vec<_> = values.into_iter().filter_map(dispatch_value).collect();
+            if responses.is_empty() {
+                // A batch of only notifications gets no response body at all.
+                Ok(String::new())
+            } else {
+                serde_json::ser::to_string(&responses)
+            }
+        }
+        Ok(value) => {
+            match dispatch_value(value) {
+                Some(resp) => serde_json::ser::to_string(&resp),
+                None => Ok(String::new()),
+            }
+        }
+        Err(err) => serde_json::ser::to_string(&ErrorJson { error: err.to_string() }),
+    };
+
+    match body {
+        Ok(body) => Ok(Response::with((status::Ok, body))),
+        Err(_) => Ok(Response::with((status::InternalServerError, FATAL_ERROR_JSON))),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RpcCall {
+    #[allow(dead_code)]
+    jsonrpc: String,
+    method: String,
+    params: serde_json::Value,
+    id: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+/// Decodes one raw call object and dispatches it, or `None` if it was a
+/// notification -- see `dispatch_rpc`. A call that doesn't even decode into
+/// an `RpcCall` is always reported as an invalid request, against whatever
+/// `id` the raw object carries or `null` if it carries none or isn't even
+/// an object -- per the spec, a request whose `id` can't be determined
+/// still gets a response, with `id: null`.
+fn dispatch_value(value: serde_json::Value) -> Option<RpcResponse> {
+    let id = value.as_object().and_then(|o| o.get("id")).cloned();
+
+    match serde_json::from_value::<RpcCall>(value) {
+        Ok(call) => dispatch_rpc(call),
+        Err(err) => Some(RpcResponse {
+            jsonrpc: "2.0",
+            result: None,
+            error: Some(RpcError { code: -32600, message: err.to_string() }),
+            id: id.unwrap_or(serde_json::Value::Null),
+        }),
+    }
+}
+
+/// Runs one JSON-RPC call and returns its response, or `None` if it was a
+/// notification (no `id`), per the spec a notification gets no response
+/// element at all -- not even on error.
+fn dispatch_rpc(call: RpcCall) -> Option<RpcResponse> {
+    let id = call.id.clone();
+    let result = run_rpc_method(&call.method, call.params);
+
+    let id = match id {
+        Some(id) => id,
+        None => return None,
+    };
+
+    Some(match result {
+        Ok(value) => RpcResponse { jsonrpc: "2.0", result: Some(value), error: None, id: id },
+        Err(err) => RpcResponse {
+            jsonrpc: "2.0",
+            result: None,
+            error: Some(RpcError { code: rpc_error_code(&err), message: err.to_string() }),
+            id: id,
+        },
+    })
+}
+
+fn run_rpc_method(method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+    let sandbox = try!(Sandbox::new());
+
+    match method {
+        "compile" => {
+            let req: CompileRequest = try!(serde_json::from_value(params).map_err(Error::InvalidParams));
+            let req = try!(req.try_into());
+            let resp = try!(sandbox.compile(&req).map_err(Error::Sandbox));
+            serde_json::to_value(&CompileResponse::from(resp)).map_err(Error::Serialization)
+        }
+        "execute" => {
+            let req: ExecuteRequest = try!(serde_json::from_value(params).map_err(Error::InvalidParams));
+            let req = try!(req.try_into());
+            let resp = try!(sandbox.execute(&req).map_err(Error::Sandbox));
+            serde_json::to_value(&ExecuteResponse::from(resp)).map_err(Error::Serialization)
+        }
+        "format" => {
+            let req: FormatRequest = try!(serde_json::from_value(params).map_err(Error::InvalidParams));
+            let resp = try!(sandbox.format(&req.into()).map_err(Error::Sandbox));
+            serde_json::to_value(&FormatResponse::from(resp)).map_err(Error::Serialization)
+        }
+        other => Err(Error::UnknownMethod(other.into())),
+    }
+}
+
+/// Maps our `Error` variants onto JSON-RPC 2.0 error codes. `-32700` and
+/// `-32600` through `-32603` are reserved by the spec; everything else from the sandbox or
+/// its request parsing lands in the implementation-defined `-32000` range.
+/// `Serialization` is kept separate from `Deserialization`: it means *we*
+/// failed to encode our own response, not that the client sent bad JSON, so
+/// it is an internal error rather than a parse error.
+fn rpc_error_code(err: &Error) -> i64 {
+    match *err {
+        Error::UnknownMethod(_) => -32601,
+        Error::InvalidParams(_) | Error::InvalidTarget(_) | Error::InvalidChannel(_) | Error::InvalidMode(_) => -32602,
+        Error::Deserialization(_) => -32700,
+        Error::Serialization(_) => -32603,
+        Error::Sandbox(_) => -32000,
+        Error::Metrics(_) => -32001,
+        Error::MetricsEncoding(_) => -32002,
+        Error::RequestMissing => -32600,
+    }
+}
+
+fn metrics(_req: &mut Request) -> IronResult<Response> {
+    use prometheus::Encoder;
+
+    let encoder = prometheus::TextEncoder::new();
+    let metric_families = prometheus::gather();
+    let mut buffer = Vec::new();
+    try!(encoder.encode(&metric_families, &mut buffer).map_err(Error::Metrics));
+    let body = try!(String::from_utf8(buffer).map_err(Error::MetricsEncoding));
+
+    let content_type = "text/plain; version=0.0.4".parse().expect("Unable to parse metrics content type");
+    Ok(Response::with((content_type, status::Ok, body)))
+}
+
+fn with_sandbox<Req, Resp, F>(route: &'static str, req: &mut Request, f: F) -> IronResult<Response>
     where F: FnOnce(Sandbox, Req) -> Result<Resp>,
           Req: Deserialize + Clone + Any + 'static,
           Resp: Serialize,
 {
+    let timer = REQUEST_DURATION.with_label_values(&[route]).start_timer();
     let response = req.get::<bodyparser::Struct<Req>>()
         .map_err(Error::Deserialization)
         .and_then(|r| r.ok_or(Error::RequestMissing))
@@ -93,10 +300,17 @@ fn with_sandbox<Req, Resp, F>(req: &mut Request, f: F) -> IronResult<Response>
             let body = try!(serde_json::ser::to_string(&resp));
             Ok(body)
         });
+    timer.observe_duration();
 
     match response {
-        Ok(body) => Ok(Response::with((status::Ok, body))),
+        Ok(body) => {
+            REQUEST_COUNTER.with_label_values(&[route, "success"]).inc();
+            Ok(Response::with((status::Ok, body)))
+        }
         Err(err) => {
+            REQUEST_COUNTER.with_label_values(&[route, "failure"]).inc();
+            REQUEST_ERRORS.with_label_values(&[route, err.variant_name()]).inc();
+
             let err = ErrorJson { error: err.to_string() };
             match serde_json::ser::to_string(&err) {
                 Ok(error_str) => Ok(Response::with((status::InternalServerError, error_str))),
@@ -127,6 +341,24 @@ quick_error! {
             cause(err)
             from()
         }
+        Metrics(err: prometheus::Error) {
+            description("unable to encode metrics")
+            display("Unable to encode metrics: {}", err)
+            cause(err)
+            from()
+        }
+        MetricsEncoding(err: ::std::string::FromUtf8Error) {
+            description("metrics encoder produced invalid UTF-8")
+            display("Metrics encoder produced invalid UTF-8: {}", err)
+            cause(err)
+            from()
+        }
+        InvalidParams(err: serde_json::Error) {
+            description("invalid params for RPC method")
+            display("Invalid params: {}", err)
+            cause(err)
+            from()
+        }
         InvalidTarget(value: String) {
             description("an invalid target was passed")
             display("The value {:?} is not a valid target", value)
@@ -139,6 +371,10 @@ quick_error! {
             description("an invalid mode was passed")
             display("The value {:?} is not a valid mode", value)
         }
+        UnknownMethod(value: String) {
+            description("an unknown RPC method was requested")
+            display("The method {:?} does not exist", value)
+        }
         RequestMissing {
             description("no request was provided")
             display("No request was provided")
@@ -148,6 +384,26 @@ quick_error! {
 
 type Result<T> = ::std::result::Result<T, Error>;
 
+impl Error {
+    /// A short, stable label identifying which variant fired, suitable for use
+    /// as a Prometheus label value.
+    fn variant_name(&self) -> &'static str {
+        match *self {
+            Error::Sandbox(_) => "sandbox",
+            Error::Serialization(_) => "serialization",
+            Error::Deserialization(_) => "deserialization",
+            Error::Metrics(_) => "metrics",
+            Error::MetricsEncoding(_) => "metrics_encoding",
+            Error::InvalidParams(_) => "invalid_params",
+            Error::InvalidTarget(_) => "invalid_target",
+            Error::InvalidChannel(_) => "invalid_channel",
+            Error::InvalidMode(_) => "invalid_mode",
+            Error::UnknownMethod(_) => "unknown_method",
+            Error::RequestMissing => "request_missing",
+        }
+    }
+}
+
 const FATAL_ERROR_JSON: &'static str =
     r#"{"error": "Multiple cascading errors occurred, abandon all hope"}"#;
 
@@ -162,6 +418,8 @@ struct CompileRequest {
     channel: String,
     mode: String,
     tests: bool,
+    #[serde(default)]
+    lint: bool,
     code: String,
 }
 
@@ -174,6 +432,7 @@ impl TryFrom<CompileRequest> for sandbox::CompileRequest {
             channel: try!(parse_channel(&me.channel)),
             mode: try!(parse_mode(&me.mode)),
             tests: me.tests,
+            lint: me.lint,
             code: me.code,
         })
     }
@@ -203,6 +462,10 @@ struct ExecuteRequest {
     channel: String,
     mode: String,
     tests: bool,
+    #[serde(default)]
+    lint: bool,
+    #[serde(default)]
+    stdin: String,
     code: String,
 }
 
@@ -214,6 +477,8 @@ impl TryFrom<ExecuteRequest> for sandbox::ExecuteRequest {
             channel: try!(parse_channel(&me.channel)),
             mode: try!(parse_mode(&me.mode)),
             tests: me.tests,
+            lint: me.lint,
+            stdin: me.stdin,
             code: me.code,
         })
     }
@@ -268,10 +533,61 @@ impl From<sandbox::FormatResponse> for FormatResponse {
     }
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct LintResponse {
+    success: bool,
+    stderr: String,
+    diagnostics: Vec<LintDiagnostic>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct LintDiagnostic {
+    level: String,
+    message: String,
+    span: LintSpan,
+    code: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct LintSpan {
+    line_start: usize,
+    line_end: usize,
+    column_start: usize,
+    column_end: usize,
+}
+
+impl From<sandbox::LintResponse> for LintResponse {
+    fn from(me: sandbox::LintResponse) -> Self {
+        LintResponse {
+            success: me.success,
+            stderr: me.stderr,
+            diagnostics: me.diagnostics.into_iter().map(LintDiagnostic::from).collect(),
+        }
+    }
+}
+
+impl From<sandbox::LintDiagnostic> for LintDiagnostic {
+    fn from(me: sandbox::LintDiagnostic) -> Self {
+        LintDiagnostic {
+            level: me.level,
+            message: me.message,
+            span: LintSpan {
+                line_start: me.span.line_start,
+                line_end: me.span.line_end,
+                column_start: me.span.column_start,
+                column_end: me.span.column_end,
+            },
+            code: me.code,
+        }
+    }
+}
+
 fn parse_target(s: &str) -> Result<sandbox::CompileTarget> {
     Ok(match s {
         "asm" => sandbox::CompileTarget::Assembly,
         "llvm-ir" => sandbox::CompileTarget::LlvmIr,
+        "wasm" => sandbox::CompileTarget::Wasm,
+        "mir" => sandbox::CompileTarget::Mir,
         _ => return Err(Error::InvalidTarget(s.into()))
     })
 }
@@ -0,0 +1,182 @@
+//! Streaming execution over WebSocket.
+//!
+//! `/execute` buffers the whole run before replying, so a caller sees
+//! nothing until the child exits (and loses everything it already printed
+//! if the run times out). This module runs the same `sandbox::ExecuteRequest`
+//! but frames stdout/stderr onto a WebSocket as they arrive, finishing with
+//! an `exit` frame. A client may send a `cancel` frame at any point to kill
+//! the child early.
+
+use std::convert::TryInto;
+use std::thread;
+
+use serde_json;
+use ws;
+
+use sandbox::{self, Sandbox};
+use {Error, ExecuteRequest};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum StreamError {
+        Sandbox(err: sandbox::Error) {
+            description("sandbox operation failed")
+            display("Sandbox operation failed: {}", err)
+            cause(err)
+            from()
+        }
+        Deserialization(err: serde_json::Error) {
+            description("unable to deserialize frame")
+            display("Unable to deserialize frame: {}", err)
+            cause(err)
+            from()
+        }
+        Request(err: Error) {
+            description("invalid execute request")
+            display("Invalid execute request: {}", err)
+            cause(err)
+            from()
+        }
+    }
+}
+
+// The crate is pinned to the old `serde_macros` plugin (see main.rs), which
+// has no support for internally-tagged enums or `rename_all` -- so each
+// server->client frame is its own plain struct carrying a literal `kind`,
+// and the client->server frame is decoded by reading `kind` as a plain
+// `String` field and matching on it by hand.
+
+#[derive(Debug, Clone, Serialize)]
+struct StdoutFrame {
+    kind: &'static str,
+    data: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct StderrFrame {
+    kind: &'static str,
+    data: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ExitFrame {
+    kind: &'static str,
+    success: bool,
+}
+
+/// A single message sent from the client to the server once the run has
+/// started. The client's first frame is the execute request itself; after
+/// that the only frame acted on is `{"kind":"cancel"}`, and anything else is
+/// ignored.
+#[derive(Debug, Clone, Deserialize)]
+struct ClientFrame {
+    kind: String,
+}
+
+/// Path the streaming endpoint is served on -- the client connects to
+/// `ws://<address>:<port>/execute/stream`, enforced below in `on_request`.
+const STREAM_RESOURCE: &'static str = "/execute/stream";
+
+/// Runs the `/execute/stream` WebSocket server on its own port. Iron itself
+/// has no WebSocket support, so this listens independently alongside it;
+/// `address` is the host the HTTP server binds, and `port` is its own
+/// setting (`PLAYGROUND_UI_STREAM_PORT`, 5001 by default), not derived from
+/// the HTTP port.
+pub fn listen(address: &str, port: u16) {
+    info!("Starting the streaming execution server on {}:{}{}", address, port, STREAM_RESOURCE);
+    ws::listen((address, port), |out| Handler { out: out, child: None })
+        .expect("Unable to start streaming execution server");
+}
+
+struct Handler {
+    out: ws::Sender,
+    child: Option<sandbox::ExecuteHandle>,
+}
+
+impl ws::Handler for Handler {
+    fn on_request(&mut self, req: &ws::Request) -> ws::Result<ws::Response> {
+        if req.resource() != STREAM_RESOURCE {
+            return Err(ws::Error::new(ws::ErrorKind::Protocol, "only /execute/stream is served"));
+        }
+        ws::Response::from_request(req)
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let text = try!(msg.into_text());
+
+        if let Some(ref child) = self.child {
+            if let Ok(frame) = serde_json::from_str::<ClientFrame>(&text) {
+                if frame.kind == "cancel" {
+                    child.kill();
+                }
+            }
+            return Ok(());
+        }
+
+        match self.start(&text) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                try!(self.send_json(&StderrFrame { kind: "stderr", data: err.to_string() }));
+                self.out.close(ws::CloseCode::Invalid)
+            }
+        }
+    }
+}
+
+impl Handler {
+    fn start(&mut self, text: &str) -> ::std::result::Result<(), StreamError> {
+        let req: ExecuteRequest = try!(serde_json::from_str(text));
+        let req: sandbox::ExecuteRequest = try!(req.try_into().map_err(StreamError::Request));
+
+        let sandbox = try!(Sandbox::new().map_err(StreamError::Sandbox));
+        let handle = try!(sandbox.execute_streaming(&req).map_err(StreamError::Sandbox));
+
+        let stdout_out = self.out.clone();
+        let stdout_rx = handle.stdout();
+        let stdout_thread = thread::spawn(move || {
+            for chunk in stdout_rx {
+                let frame = StdoutFrame { kind: "stdout", data: chunk };
+                if stdout_out.send(serde_json::to_string(&frame).unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stderr_out = self.out.clone();
+        let stderr_rx = handle.stderr();
+        let stderr_thread = thread::spawn(move || {
+            for chunk in stderr_rx {
+                let frame = StderrFrame { kind: "stderr", data: chunk };
+                if stderr_out.send(serde_json::to_string(&frame).unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let exit_out = self.out.clone();
+        let exit_rx = handle.exit();
+        thread::spawn(move || {
+            let success = exit_rx.recv().ok();
+
+            // Wait for the stdout/stderr forwarders to drain whatever is
+            // still queued before closing -- otherwise a chunk sitting in
+            // either channel when the child exits gets dropped on the floor
+            // instead of reaching the client.
+            let _ = stdout_thread.join();
+            let _ = stderr_thread.join();
+
+            if let Some(success) = success {
+                let frame = ExitFrame { kind: "exit", success: success };
+                let _ = exit_out.send(serde_json::to_string(&frame).unwrap());
+            }
+            let _ = exit_out.close(ws::CloseCode::Normal);
+        });
+
+        self.child = Some(handle);
+        Ok(())
+    }
+
+    fn send_json<T: ::serde::Serialize>(&self, value: &T) -> ws::Result<()> {
+        self.out.send(serde_json::to_string(value).unwrap())
+    }
+}